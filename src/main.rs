@@ -1,6 +1,6 @@
 use anyhow::bail;
-use std::time::Duration;
-use stp_rs::stp::eth::EthSwitch;
+use std::{collections::HashMap, time::Duration};
+use stp_rs::stp::eth::EthRouter;
 
 /// How often switches broadcast their routing state to neighbors
 const BPDU_RESEND_FREQ: Duration = Duration::from_secs(2);
@@ -10,10 +10,33 @@ const BPDU_RESEND_FREQ: Duration = Duration::from_secs(2);
 /// event loop.
 const SWITCH_TICK_SPEED: Option<Duration> = Some(Duration::from_micros(1000));
 
+/// How long a learned forwarding-table entry is trusted before it's aged
+/// out, matching the default of real bridges.
+const FWD_AGE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a bpdu's information is trusted before it's considered stale,
+/// matching the 802.1D default.
+const BPDU_MAX_AGE: Duration = Duration::from_secs(20);
+
+/// How long a port spends in `Learning` before moving to `Designated`,
+/// matching the 802.1D default.
+const FORWARD_DELAY: Duration = Duration::from_secs(15);
+
 fn main() -> anyhow::Result<()> {
     let Some(switch_name) = std::env::args().nth(1) else {
         bail!("First argument must be the switch name");
     };
-    let switch = EthSwitch::build(&switch_name, BPDU_RESEND_FREQ, SWITCH_TICK_SPEED)?;
-    switch.run(Duration::from_millis(500))
+    // No per-port VLAN config is wired up from the CLI yet, so every port
+    // defaults to the switch's native VLAN (access VID 1).
+    let vlan_config = HashMap::new();
+    let switch = EthRouter::build(
+        &switch_name,
+        &vlan_config,
+        BPDU_RESEND_FREQ,
+        FWD_AGE_TIMEOUT,
+        BPDU_MAX_AGE,
+        FORWARD_DELAY,
+        SWITCH_TICK_SPEED,
+    )?;
+    switch.run()
 }
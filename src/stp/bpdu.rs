@@ -1,9 +1,13 @@
 use bytemuck::{Pod, Zeroable};
 use pnet::{
-    packet::ethernet::{EthernetPacket, MutableEthernetPacket},
+    packet::{
+        ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket},
+        vlan::{MutableVlanPacket, VlanPacket},
+        MutablePacket, Packet,
+    },
     util::MacAddr,
 };
-use std::mem;
+use std::{mem, time::Duration};
 
 /// A bridge protocol data unit packet. This is not full-spec. I'm
 /// choosing a subset of fields and using aligned data types instead of
@@ -16,6 +20,42 @@ pub struct Bpdu {
     root_cost: u8,
     root_id: [u8; 6],
     bridge_id: [u8; 6],
+    /// Explicit padding so the u16 timers below start at an even offset.
+    /// bytemuck::Pod requires the struct to have no compiler-inserted
+    /// padding, and the fields above sum to an odd number of bytes.
+    _reserved: u8,
+    /// Seconds since this bpdu's information originated at the root,
+    /// incremented as it's held and relayed. Compared against `max_age`
+    /// to decide when stale root information should be discarded.
+    message_age: u16,
+    /// How many seconds of `message_age` a bridge will tolerate before
+    /// treating this bpdu's information as stale.
+    max_age: u16,
+    /// How often the root re-advertises this bpdu, in seconds.
+    hello_time: u16,
+    /// Seconds a port spends in `Learning` before moving to `Designated`.
+    forward_delay: u16,
+    /// Set by a designated port proposing that the receiving bridge adopt
+    /// this bpdu's information and move straight to forwarding, instead of
+    /// waiting out `forward_delay`. Paired with `agreement` to negotiate
+    /// rapid convergence after a topology change.
+    proposal: u8,
+    /// Set by a bridge replying to a `proposal` bpdu, once it has
+    /// synchronized (blocked any of its own non-edge designated ports that
+    /// could otherwise form a transient loop) and is ready for the
+    /// proposing port to move straight to forwarding.
+    agreement: u8,
+    /// Set by a bridge for a bounded interval after it changes a port's
+    /// role, and propagated by every bridge that receives it while still
+    /// set. Tells recipients to shorten their own forwarding-table aging
+    /// timeout so stale entries left by the old topology are flushed
+    /// quickly instead of blackholing traffic.
+    tc: u8,
+    /// Remaining hop budget for relaying `tc`, decremented by each bridge
+    /// that relays it onward. Bounds propagation so a tc notice can't keep
+    /// bouncing between bridges forever in a looped topology; a bridge
+    /// stops relaying once this reaches 0.
+    tc_hops: u8,
 }
 
 /// A buffer used to construct Bpdu packets. All Bpdu
@@ -29,15 +69,99 @@ impl Bpdu {
     pub const BPDU_MAC: MacAddr = MacAddr(0x01, 0x80, 0xc2, 0x0, 0x0, 0x0);
 
     /// Builds a new bpdu type, casting Mac addresses into raw octets that
-    /// satisfy bytemuck trait bounds.
-    pub fn new(root_cost: u8, root_id: MacAddr, bridge_id: MacAddr) -> Self {
+    /// satisfy bytemuck trait bounds. `message_age` always starts at 0, as
+    /// for freshly originated information; a bridge adopting a neighbor's
+    /// information carries the neighbor's age over with `with_message_age`.
+    pub fn new(
+        root_cost: u8,
+        root_id: MacAddr,
+        bridge_id: MacAddr,
+        max_age: u16,
+        hello_time: u16,
+        forward_delay: u16,
+    ) -> Self {
         Bpdu {
             root_cost,
             root_id: root_id.octets(),
             bridge_id: bridge_id.octets(),
+            _reserved: 0,
+            message_age: 0,
+            max_age,
+            hello_time,
+            forward_delay,
+            proposal: 0,
+            agreement: 0,
+            tc: 0,
+            tc_hops: 0,
         }
     }
 
+    /// Returns a copy of this bpdu with `message_age` overridden, for a
+    /// bridge re-advertising a neighbor's (or its own aged) information
+    /// instead of freshly originating it. Without this, every relay would
+    /// re-originate at age 0 and `max_age` could never be reached as long
+    /// as some bridge along the path kept talking.
+    #[inline]
+    pub fn with_message_age(mut self, message_age: u16) -> Self {
+        self.message_age = message_age;
+        self
+    }
+
+    /// Returns a copy of this bpdu with the proposal bit set, for a
+    /// designated port asking the bridge on the other end to sync and
+    /// converge immediately rather than wait out `forward_delay`.
+    #[inline]
+    pub fn with_proposal(mut self) -> Self {
+        self.proposal = 1;
+        self
+    }
+
+    /// Returns a copy of this bpdu with the agreement bit set, for a reply
+    /// telling a proposing port it's clear to move straight to forwarding.
+    #[inline]
+    pub fn with_agreement(mut self) -> Self {
+        self.agreement = 1;
+        self
+    }
+
+    #[inline]
+    pub fn is_proposal(&self) -> bool {
+        self.proposal != 0
+    }
+
+    #[inline]
+    pub fn is_agreement(&self) -> bool {
+        self.agreement != 0
+    }
+
+    /// Returns a copy of this bpdu with the topology-change bit set, for a
+    /// bridge announcing (or relaying) that a port's role just changed
+    /// somewhere in the tree.
+    #[inline]
+    pub fn with_tc(mut self) -> Self {
+        self.tc = 1;
+        self
+    }
+
+    #[inline]
+    pub fn is_tc(&self) -> bool {
+        self.tc != 0
+    }
+
+    /// Returns a copy of this bpdu with its remaining tc relay budget set
+    /// to `hops`.
+    #[inline]
+    pub fn with_tc_hops(mut self, hops: u8) -> Self {
+        self.tc_hops = hops;
+        self
+    }
+
+    /// Remaining hops this bpdu's tc notice may still be relayed for.
+    #[inline]
+    pub fn tc_hops(&self) -> u8 {
+        self.tc_hops
+    }
+
     /// Returns a u8 buffer capable of holding exactly the size of a bpdu ethernet packet.
     pub fn make_buf() -> BpduBuf {
         BpduBuf(vec![
@@ -47,6 +171,18 @@ impl Bpdu {
         ])
     }
 
+    /// Returns a u8 buffer capable of holding exactly the size of an
+    /// 802.1Q-tagged bpdu ethernet packet (used on trunk ports, where each
+    /// VLAN runs its own spanning tree and needs its own tagged exchange).
+    pub fn make_tagged_buf() -> BpduBuf {
+        BpduBuf(vec![
+            0;
+            EthernetPacket::minimum_packet_size()
+                + VlanPacket::minimum_packet_size()
+                + mem::size_of::<Bpdu>()
+        ])
+    }
+
     #[inline]
     pub fn cost(&self) -> u8 {
         self.root_cost
@@ -62,6 +198,44 @@ impl Bpdu {
         self.bridge_id.into()
     }
 
+    #[inline]
+    pub fn message_age(&self) -> u16 {
+        self.message_age
+    }
+
+    #[inline]
+    pub fn max_age(&self) -> u16 {
+        self.max_age
+    }
+
+    #[inline]
+    pub fn hello_time(&self) -> u16 {
+        self.hello_time
+    }
+
+    #[inline]
+    pub fn forward_delay(&self) -> u16 {
+        self.forward_delay
+    }
+
+    /// Returns a copy of this bpdu with `message_age` advanced by the
+    /// seconds spanned by however many whole `hello_time` intervals have
+    /// elapsed since it was last aged, saturating rather than wrapping past
+    /// `u16::MAX`.
+    pub fn aged_by(&self, elapsed: Duration) -> Bpdu {
+        let hello_secs = self.hello_time.max(1) as u64;
+        let elapsed_secs = (elapsed.as_secs() / hello_secs * hello_secs).min(u16::MAX as u64) as u16;
+        let mut aged = *self;
+        aged.message_age = aged.message_age.saturating_add(elapsed_secs);
+        aged
+    }
+
+    /// Whether this bpdu's information is too old to keep trusting.
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.message_age >= self.max_age
+    }
+
     /// Makes a bpdu ethernet packet in the given `bpdu_buf`.
     pub fn make_packet<'a>(
         &self,
@@ -76,4 +250,52 @@ impl Bpdu {
         pkt.set_destination(Self::BPDU_MAC);
         pkt.consume_to_immutable()
     }
+
+    /// Makes an 802.1Q-tagged bpdu ethernet packet in the given `bpdu_buf`,
+    /// carrying the VID that this bpdu's spanning tree applies to. Used on
+    /// trunk ports, which multiplex the per-VLAN spanning trees of every
+    /// VLAN they carry over one link.
+    pub fn make_tagged_packet<'a>(
+        &self,
+        bpdu_buf: &'a mut BpduBuf,
+        src_mac: MacAddr,
+        vid: u16,
+    ) -> EthernetPacket<'a> {
+        let mut pkt = MutableEthernetPacket::new(&mut bpdu_buf.0).expect(
+            "Tagged bpdu packet size should be constant, and the buf should always accomodate what's needed",
+        );
+        pkt.set_source(src_mac);
+        pkt.set_destination(Self::BPDU_MAC);
+        pkt.set_ethertype(EtherTypes::Vlan);
+
+        let mut vlan = MutableVlanPacket::new(pkt.payload_mut())
+            .expect("Tagged bpdu packet buf should always accomodate a vlan header");
+        vlan.set_vlan_identifier(vid);
+        vlan.set_ethertype(EtherType(0));
+        vlan.set_payload(bytemuck::bytes_of(self));
+
+        pkt.consume_to_immutable()
+    }
+
+    /// Returns whether a packet is marked for the purpose of ethernet
+    /// routing, and if so, parses out the bpdu and, for a tagged packet,
+    /// the VID it was sent for. An untagged bpdu has no VID of its own: it
+    /// implicitly belongs to whatever VLAN the receiving access port serves.
+    /// Panics if the packet matches the BPDU mac address but cannot be
+    /// deserialized. Such a case indicates a bug or some serious
+    /// misunderstanding of the network.
+    pub fn try_routing(pkt: &EthernetPacket) -> Option<(Bpdu, Option<u16>)> {
+        if Bpdu::BPDU_MAC != pkt.get_destination() {
+            return None;
+        }
+
+        if pkt.get_ethertype() == EtherTypes::Vlan {
+            let vlan = VlanPacket::new(pkt.payload())
+                .expect("Tagged bpdu packet should always carry a full vlan header");
+            let bpdu = *bytemuck::from_bytes::<Bpdu>(vlan.payload());
+            return Some((bpdu, Some(vlan.get_vlan_identifier())));
+        }
+
+        Some((*bytemuck::from_bytes::<Bpdu>(pkt.payload()), None))
+    }
 }
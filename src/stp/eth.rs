@@ -4,145 +4,594 @@ use pnet::{
     datalink::{
         self, Channel::Ethernet, Config, DataLinkReceiver, DataLinkSender, NetworkInterface,
     },
-    packet::{ethernet::EthernetPacket, Packet},
+    packet::{
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        vlan::{MutableVlanPacket, VlanPacket},
+        MutablePacket, Packet,
+    },
     util::MacAddr,
 };
 use std::{
     cmp::Ordering,
-    collections::HashMap,
-    io::ErrorKind,
-    mem,
+    collections::{HashMap, HashSet},
+    io,
+    sync::mpsc::{self, Receiver, Sender},
     time::{Duration, Instant},
 };
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum PortState {
-    /// The initial state. Packets aren't forwarded, but origins are added
-    /// to the forwarding table.
-    Learning,
-    /// The port is the switch's path to the root. All traffic is served.
-    Root,
-    /// This port is part of a loop. Only BPDU packets are accepted.
-    Block,
-    /// This port services other nodes' access to the root. All traffic is served.
-    Forward,
+/// Abstracts over how a port actually moves raw ethernet frames, so the
+/// switch can run over real interfaces (pnet datalink), a future TUN/TAP
+/// backend, or an in-memory link wiring two switches together in one
+/// process. `EthPort`/`EthRouter` are generic over this instead of being
+/// hard-wired to `pnet::datalink`.
+pub trait VirtualInterface {
+    /// This interface's own mac address.
+    fn mac(&self) -> MacAddr;
+
+    /// Blocks until a frame arrives or the interface's own poll timeout
+    /// elapses, in which case it returns an `io::ErrorKind::TimedOut`
+    /// error. The returned slice borrows internal buffer space and is only
+    /// valid until the next call to `read`.
+    fn read(&mut self) -> io::Result<&[u8]>;
+
+    /// Sends a raw ethernet frame out this interface.
+    fn write(&mut self, frame: &[u8]) -> io::Result<()>;
 }
 
-struct EthPort {
+/// The original transport: a real interface opened through
+/// `pnet::datalink::channel`.
+pub struct PnetInterface {
     mac: MacAddr,
     tx: Box<dyn DataLinkSender>,
-    state: PortState,
+    rx: Box<dyn DataLinkReceiver>,
+    /// `VirtualInterface::read` must return a borrow of `&mut self` rather
+    /// than of the `DataLinkReceiver`'s own internal buffer, so each read
+    /// is copied in here.
+    buf: Vec<u8>,
 }
 
-impl EthPort {
-    /// Builds an abstraction that supports sending and receiving network packets from
-    /// an ethernet port. Receive blocks until a packet arries or `poll_timeout` has elapsed.
-    pub fn build(
-        intf: &NetworkInterface,
-        poll_timeout: Option<Duration>,
-    ) -> anyhow::Result<(Self, Box<dyn DataLinkReceiver>)> {
+impl PnetInterface {
+    /// Opens a pnet datalink channel on `intf`. Receive blocks until a
+    /// packet arrives or `poll_timeout` has elapsed.
+    pub fn build(intf: &NetworkInterface, poll_timeout: Option<Duration>) -> anyhow::Result<Self> {
         let port_cfg = Config {
             read_timeout: poll_timeout,
             ..Config::default()
         };
-        let Ok(Ethernet(tx, rx)) = datalink::channel(&intf, port_cfg) else {
+        let Ok(Ethernet(tx, rx)) = datalink::channel(intf, port_cfg) else {
             bail!("Failed to parse ethernet channel on interface: {:#?}", intf);
         };
         let Some(mac) = intf.mac else {
             bail!("Cannot create an eth port without a mac address");
         };
-        Ok((
-            Self {
-                mac,
-                state: PortState::Learning,
-                tx,
-            },
+        Ok(Self {
+            mac,
+            tx,
             rx,
-        ))
+            buf: Vec::new(),
+        })
     }
+}
 
-    /// Returns whether a packet is marked for the purpose of ethernet routing
-    /// Panics if the packet matches the BPDU mac address but cannot be serialized.
-    /// Such a case indicates a bug or some serious misunderstanding of the network.
-    pub fn try_routing<'a>(pkt: &'a EthernetPacket) -> Option<&'a Bpdu> {
-        if Bpdu::BPDU_MAC != pkt.get_destination() {
-            return None;
-        };
-        Some(bytemuck::from_bytes(pkt.payload()))
+impl VirtualInterface for PnetInterface {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn read(&mut self) -> io::Result<&[u8]> {
+        let bytes = self.rx.next()?;
+        self.buf.clear();
+        self.buf.extend_from_slice(bytes);
+        Ok(&self.buf)
+    }
+
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .build_and_send(1, frame.len(), &mut |outbound| {
+                outbound.clone_from_slice(frame);
+            })
+            .unwrap_or(Ok(()))
+    }
+}
+
+/// An in-memory, full-duplex link built from a pair of mpsc channels.
+/// `ChannelInterface::paired` hands back both ends, so tests can wire
+/// several `EthRouter`s together (driving each one's `tick` in lockstep,
+/// since `EthRouter::run` never returns) and assert on their converged
+/// `PortState` without mininet or qemu.
+pub struct ChannelInterface {
+    mac: MacAddr,
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    poll_timeout: Option<Duration>,
+    buf: Vec<u8>,
+}
+
+impl ChannelInterface {
+    /// Builds the two ends of an in-memory link. `mac_a`/`mac_b` become
+    /// each end's own mac address; `poll_timeout` mirrors
+    /// `PnetInterface`'s read timeout, with `None` meaning block forever.
+    pub fn paired(
+        mac_a: MacAddr,
+        mac_b: MacAddr,
+        poll_timeout: Option<Duration>,
+    ) -> (Self, Self) {
+        let (tx_a, rx_b) = mpsc::channel();
+        let (tx_b, rx_a) = mpsc::channel();
+        (
+            ChannelInterface {
+                mac: mac_a,
+                tx: tx_a,
+                rx: rx_a,
+                poll_timeout,
+                buf: Vec::new(),
+            },
+            ChannelInterface {
+                mac: mac_b,
+                tx: tx_b,
+                rx: rx_b,
+                poll_timeout,
+                buf: Vec::new(),
+            },
+        )
+    }
+}
+
+impl VirtualInterface for ChannelInterface {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn read(&mut self) -> io::Result<&[u8]> {
+        let frame = match self.poll_timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => {
+                    io::Error::new(io::ErrorKind::TimedOut, e)
+                }
+                mpsc::RecvTimeoutError::Disconnected => {
+                    io::Error::new(io::ErrorKind::BrokenPipe, e)
+                }
+            }),
+            None => self
+                .rx
+                .recv()
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e)),
+        }?;
+        self.buf = frame;
+        Ok(&self.buf)
+    }
+
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(frame.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum PortState {
+    /// The initial state. Packets aren't forwarded, but origins are added
+    /// to the forwarding table.
+    Learning,
+    /// The port is the switch's path to the root. All traffic is served.
+    Root,
+    /// This port serves other nodes' access to the root. All traffic is served.
+    Designated,
+    /// This port just won the right to become `Designated` but hasn't
+    /// synced with whoever's downstream yet: it sends proposal bpdus
+    /// (`broadcast_bpdu`) and discards client traffic like a blocked port
+    /// until it either gets an agreement bpdu back (promoted immediately,
+    /// see `run`) or `forward_delay` elapses with none (promoted the slow
+    /// way, same as a plain `Learning` port). Without this intermediate
+    /// state a proposing port would forward immediately, defeating the
+    /// loop-free guarantee the proposal/agreement handshake exists for.
+    Proposing,
+    /// This port lost to a better path through a different bridge on its
+    /// segment. Only BPDU packets are accepted; a live Alternate is what
+    /// `reelect_root` promotes if the current root port goes stale.
+    Alternate,
+    /// This port is part of a loop: it hears a bpdu with a worse path to
+    /// the same root whose upstream bridge is not this one (including this
+    /// bridge's own advertised info reflected back). Only BPDU packets are
+    /// accepted.
+    Backup,
+}
+
+impl PortState {
+    /// Whether client traffic is served on a port in this state.
+    fn is_forwarding(self) -> bool {
+        matches!(self, PortState::Root | PortState::Designated)
+    }
+}
+
+/// Describes which VLAN(s) a port carries and how frames on the wire are
+/// tagged. Mirrors the access/trunk distinction of a real switch: access
+/// ports serve a single untagged VLAN (typically a host), trunk ports
+/// multiplex several 802.1Q-tagged VLANs (typically an inter-switch link).
+#[derive(Debug, Clone)]
+pub enum PortMode {
+    /// Untagged port bound to a single VID.
+    Access(u16),
+    /// Tagged port carrying every VID in the set.
+    Trunk(HashSet<u16>),
+}
+
+impl PortMode {
+    /// Returns whether this port is a member of `vid`'s broadcast domain.
+    fn has_vlan(&self, vid: u16) -> bool {
+        match self {
+            PortMode::Access(access_vid) => *access_vid == vid,
+            PortMode::Trunk(vids) => vids.contains(&vid),
+        }
+    }
+
+    /// Every VLAN this port participates in.
+    fn vlans(&self) -> Vec<u16> {
+        match self {
+            PortMode::Access(vid) => vec![*vid],
+            PortMode::Trunk(vids) => vids.iter().copied().collect(),
+        }
+    }
+}
+
+struct EthPort<T: VirtualInterface> {
+    iface: T,
+    mode: PortMode,
+    /// Spanning tree state of this port in each VLAN it serves. A loop can
+    /// block this port in one VLAN while it keeps forwarding in another.
+    vlan_states: HashMap<u16, PortState>,
+    /// When this port most recently entered `Learning` or `Proposing` in a
+    /// given VLAN. Consulted against that VLAN's `forward_delay` to decide
+    /// when the port is allowed to move on to `Designated`.
+    learning_since: HashMap<u16, Instant>,
+    /// The most recent bpdu heard on this port for each VLAN, and when it
+    /// arrived. Aged out by `EthRouter::age_bpdus`, which is what lets a
+    /// dead root (or a dead path to it) be detected and re-elected around.
+    heard_bpdu: HashMap<u16, (Bpdu, Instant)>,
+    /// Whether this port is believed to serve only end hosts (no live bpdu
+    /// held from a bridge on the other end) in a given VLAN. An edge port skips
+    /// straight to `Designated` instead of waiting out `forward_delay`, and
+    /// is exempt from the resync that `reset_root` forces on other
+    /// designated ports, since there's no neighbor bridge to loop with.
+    edge: HashMap<u16, bool>,
+}
+
+impl<T: VirtualInterface> EthPort<T> {
+    /// Wraps an already-built `VirtualInterface` as a switch port serving
+    /// `mode`.
+    fn new(iface: T, mode: PortMode) -> Self {
+        let now = Instant::now();
+        let vlan_states = mode
+            .vlans()
+            .into_iter()
+            .map(|vid| (vid, PortState::Learning))
+            .collect();
+        let learning_since = mode.vlans().into_iter().map(|vid| (vid, now)).collect();
+        let edge = mode.vlans().into_iter().map(|vid| (vid, false)).collect();
+        Self {
+            iface,
+            mode,
+            vlan_states,
+            learning_since,
+            heard_bpdu: HashMap::new(),
+            edge,
+        }
+    }
+
+    /// Whether this port is currently believed to be an edge port (host
+    /// only, no bridge) in `vid`.
+    fn is_edge(&self, vid: u16) -> bool {
+        self.edge.get(&vid).copied().unwrap_or(false)
+    }
+
+    /// This port's spanning tree state in `vid`, or `None` if the port
+    /// isn't a member of that VLAN.
+    fn state(&self, vid: u16) -> Option<PortState> {
+        self.vlan_states.get(&vid).copied()
+    }
+
+    fn set_state(&mut self, vid: u16, state: PortState) {
+        if matches!(state, PortState::Learning | PortState::Proposing) {
+            self.learning_since.insert(vid, Instant::now());
+        }
+        self.vlan_states.insert(vid, state);
     }
 }
 
-pub struct EthRouter {
-    ports: Vec<EthPort>,
-    inbound: Vec<Box<dyn DataLinkReceiver>>,
+/// A learned `(mac, vid) -> port` mapping, along with when it was last
+/// refreshed so stale entries can be aged out.
+struct FwdEntry {
+    port: usize,
+    last_seen: Instant,
+}
+
+/// How often `housekeep` runs, expressed as a fraction of `fwd_age_timeout`.
+/// Frequent enough that a stale mapping is never kept around for much
+/// longer than the timeout itself.
+const HOUSEKEEP_FRACTION: u32 = 4;
+
+/// How long a port waits in `Learning` without hearing any bpdu before
+/// it's assumed to be an edge port (serving a host, not a bridge) and
+/// promoted straight to `Designated`. Short relative to `forward_delay`,
+/// since a real neighbor bridge announces itself well within it.
+const EDGE_DETECT_WINDOW: Duration = Duration::from_millis(500);
+
+/// Hop budget a freshly originated topology-change notice is given.
+/// Bounds how many bridges will relay it onward, so a tc notice in a
+/// looped topology decrements to 0 and stops instead of bouncing between
+/// bridges forever.
+const MAX_TC_HOPS: u8 = 20;
+
+pub struct EthRouter<T: VirtualInterface> {
+    ports: Vec<EthPort<T>>,
     switch_id: MacAddr,
-    curr_bpdu: Bpdu,
+    /// The best known bpdu for each VLAN this switch serves. Every VLAN
+    /// runs its own, independent spanning tree.
+    curr_bpdu: HashMap<u16, Bpdu>,
     bpdu_buf: BpduBuf,
+    tagged_bpdu_buf: BpduBuf,
     bpdu_resend_timeout: Duration,
     last_resent_bpdu: Instant,
-    /// MacAddr is the destination mac, and the value usize is an
-    /// index into the egress table.
-    fwd_table: HashMap<MacAddr, usize>,
+    /// (MacAddr, vid) is the destination mac and the VLAN it was learned
+    /// on. The same mac can sit behind different ports in different VLANs.
+    /// Entries older than `fwd_age_timeout` are evicted by `housekeep`, so a
+    /// host that moves or disappears can't blackhole traffic forever.
+    fwd_table: HashMap<(MacAddr, u16), FwdEntry>,
+    fwd_age_timeout: Duration,
+    last_housekeep: Instant,
+    /// When the current topology-change window closes, if one is open.
+    /// Set by `note_topology_change` and read by `housekeep`/`broadcast_bpdu`.
+    tc_expires_at: Option<Instant>,
+    /// The shortened aging timeout to use while a topology-change window
+    /// is open, in place of `fwd_age_timeout`.
+    tc_fwd_age_timeout: Duration,
+    /// Remaining hops the currently open tc window may still be relayed
+    /// for. Set by `note_topology_change`/`relay_topology_change` and read
+    /// by `broadcast_bpdu`, which stops setting the tc bit once it hits 0.
+    tc_hops_remaining: u8,
 }
 
-impl EthRouter {
-    /// Queries ethernet interfaces and opens read/write connections with all
-    /// mininet ports. Assigns a mac address to represent the whole switch and
-    /// establishes an initial Bpdu for this switch.
-    pub fn build(
-        switch_name: &str,
+impl<T: VirtualInterface> EthRouter<T> {
+    /// Builds a router directly from already-constructed interfaces, each
+    /// paired with the `PortMode` it should serve. This is the transport-
+    /// agnostic constructor that both `EthRouter::build` (pnet discovery)
+    /// and tests (wiring up `ChannelInterface` links) funnel through.
+    ///
+    /// `fwd_age_timeout` bounds how long a learned `fwd_table` entry is
+    /// trusted before `housekeep` evicts it; real bridges default this to
+    /// around 300 seconds.
+    ///
+    /// `bpdu_resend_timeout` doubles as this switch's own bpdu `hello_time`;
+    /// `max_age` and `forward_delay` become the starting values this switch
+    /// advertises as root until a bridge with better information is heard,
+    /// at which point its timers take over for the rest of the tree (as on
+    /// a real bridge, only the root's timers matter).
+    pub fn from_ports(
+        ports: Vec<(T, PortMode)>,
         bpdu_resend_timeout: Duration,
-        eth_poll_timeout: Option<Duration>,
+        fwd_age_timeout: Duration,
+        max_age: Duration,
+        forward_delay: Duration,
     ) -> anyhow::Result<Self> {
-        let interfaces = datalink::interfaces();
-        let mut ports = Vec::with_capacity(interfaces.len());
-        let mut inbound = Vec::with_capacity(interfaces.len());
         let mut switch_id = MacAddr::broadcast();
-
-        // Note: Port egress and ingress are separated because simultanous
-        // borrows to both the tx and rx are almost always needed. That supports
-        // no data copying except from the ethernet inflow buffer into
-        // the outflow buffer.
-
-        let mn_name = format!("{switch_name}-eth");
-        for intf in datalink::interfaces()
-            .iter()
-            .filter(|intf| intf.name.contains(&mn_name))
-        {
-            let (port, port_rx) = EthPort::build(intf, eth_poll_timeout)?;
-            switch_id = switch_id.min(port.mac);
-            ports.push(port);
-            inbound.push(port_rx);
+        let mut built_ports = Vec::with_capacity(ports.len());
+        for (iface, mode) in ports {
+            switch_id = switch_id.min(iface.mac());
+            built_ports.push(EthPort::new(iface, mode));
         }
 
         if switch_id == MacAddr::broadcast() {
             bail!("Failed to identify any viable interfaces for this switch");
         }
 
+        let hello_time = bpdu_resend_timeout.as_secs().min(u16::MAX as u64) as u16;
+        let max_age = max_age.as_secs().min(u16::MAX as u64) as u16;
+        let forward_delay = forward_delay.as_secs().min(u16::MAX as u64) as u16;
+
+        let mut curr_bpdu = HashMap::new();
+        for port in &built_ports {
+            for vid in port.mode.vlans() {
+                curr_bpdu.entry(vid).or_insert_with(|| {
+                    Bpdu::new(0, switch_id, switch_id, max_age, hello_time, forward_delay)
+                });
+            }
+        }
+
         Ok(EthRouter {
-            ports,
-            inbound,
+            ports: built_ports,
             switch_id,
-            curr_bpdu: Bpdu::new(0, switch_id, switch_id),
+            curr_bpdu,
             bpdu_buf: Bpdu::make_buf(),
+            tagged_bpdu_buf: Bpdu::make_tagged_buf(),
             bpdu_resend_timeout,
             last_resent_bpdu: Instant::now()
                 .checked_sub(bpdu_resend_timeout)
-                .unwrap_or_else(|| Instant::now()),
+                .unwrap_or_else(Instant::now),
             fwd_table: HashMap::new(),
+            fwd_age_timeout,
+            last_housekeep: Instant::now(),
+            tc_expires_at: None,
+            tc_fwd_age_timeout: fwd_age_timeout,
+            tc_hops_remaining: 0,
         })
     }
 
-    /// Sends the packet to the given outbound transmitter.
-    /// The given packet is copied directly into the send buffer.
-    fn send(tx: &mut Box<dyn DataLinkSender>, pkt: &EthernetPacket) {
-        tx.build_and_send(1, pkt.packet().len(), &mut |outbound| {
-            outbound.clone_from_slice(pkt.packet());
-        });
+    /// Learns (or refreshes) that `mac` sits behind `port` in `vid`.
+    fn learn(&mut self, mac: MacAddr, vid: u16, port: usize) {
+        self.fwd_table.insert(
+            (mac, vid),
+            FwdEntry {
+                port,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    /// Looks up the live egress port learned for `mac` in `vid`, if any.
+    fn lookup(&self, mac: MacAddr, vid: u16) -> Option<usize> {
+        let timeout = if self.tc_active() {
+            self.tc_fwd_age_timeout
+        } else {
+            self.fwd_age_timeout
+        };
+        self.fwd_table
+            .get(&(mac, vid))
+            .filter(|entry| entry.last_seen.elapsed() < timeout)
+            .map(|entry| entry.port)
+    }
+
+    /// Evicts forwarding-table entries that haven't been refreshed within
+    /// the current aging timeout, so a host that moved or disappeared
+    /// doesn't keep blackholing traffic at its old port. Shortened to
+    /// `tc_fwd_age_timeout` while a topology-change window is open, so the
+    /// table relearns quickly around a just-changed topology instead of
+    /// waiting out the normal, much longer `fwd_age_timeout`.
+    fn housekeep(&mut self) {
+        let timeout = if self.tc_active() {
+            self.tc_fwd_age_timeout
+        } else {
+            self.fwd_age_timeout
+        };
+        self.fwd_table
+            .retain(|_, entry| entry.last_seen.elapsed() < timeout);
+    }
+
+    /// Whether a topology-change window is currently open.
+    fn tc_active(&self) -> bool {
+        self.tc_expires_at.is_some_and(|expires| Instant::now() < expires)
+    }
+
+    /// Records that `vid`'s topology just changed *at this bridge*: shortens
+    /// the forwarding-table aging timeout to `vid`'s `forward_delay` for the
+    /// next `forward_delay` seconds, and keeps advertising the tc bit for
+    /// that long (applied lazily in `broadcast_bpdu`) so neighbors adopt the
+    /// same fast aging and relay the notice onward. Originates a fresh
+    /// `MAX_TC_HOPS` relay budget, since this bridge is the one introducing
+    /// the notice.
+    fn note_topology_change(&mut self, vid: u16) {
+        self.open_tc_window(vid, MAX_TC_HOPS);
+    }
+
+    /// Records that a neighbor is relaying a tc notice it received, rather
+    /// than one this bridge originated itself. `hops_left` is the relay
+    /// budget the neighbor's bpdu still carried; this bridge only opens its
+    /// own window (and keeps relaying) if there's budget left, decrementing
+    /// it by one hop. Without this bound, two bridges that keep relaying
+    /// each other's notices back and forth would never let a tc window
+    /// close for good.
+    fn relay_topology_change(&mut self, vid: u16, hops_left: u8) {
+        if hops_left == 0 {
+            return;
+        }
+        self.open_tc_window(vid, hops_left - 1);
+    }
+
+    /// Shared implementation behind `note_topology_change` and
+    /// `relay_topology_change`: opens (or refreshes) `vid`'s tc window with
+    /// `hops` remaining to relay it.
+    fn open_tc_window(&mut self, vid: u16, hops: u8) {
+        let forward_delay = self
+            .curr_bpdu
+            .get(&vid)
+            .map(|bpdu| bpdu.forward_delay())
+            .unwrap_or(0);
+        let window = Duration::from_secs(forward_delay as u64);
+        self.tc_fwd_age_timeout = window;
+        self.tc_expires_at = Some(Instant::now() + window);
+        self.tc_hops_remaining = hops;
+    }
+
+    /// Sends the packet out `iface`. The given packet is copied directly
+    /// into the interface's own send buffer.
+    fn send(iface: &mut T, pkt: &EthernetPacket) {
+        if let Err(e) = iface.write(pkt.packet()) {
+            eprintln!("Failed to send packet: {e:#?}");
+        }
+    }
+
+    /// Returns the VLAN a client frame belongs to: the tag it carries if
+    /// it's 802.1Q-tagged (only valid on a trunk port serving that VID), or
+    /// the ingress port's own access VID if it arrived untagged. Returns
+    /// `None` if the frame's VLAN isn't one the ingress port is a member of
+    /// (e.g. an untagged frame on a trunk port, or a tag for a VID the
+    /// trunk doesn't carry).
+    fn frame_vid(&self, portnum_in: usize, eth_pkt: &EthernetPacket) -> Option<u16> {
+        let mode = &self.ports[portnum_in].mode;
+        if eth_pkt.get_ethertype() == EtherTypes::Vlan {
+            let vid = VlanPacket::new(eth_pkt.payload())?.get_vlan_identifier();
+            mode.has_vlan(vid).then_some(vid)
+        } else {
+            match mode {
+                PortMode::Access(vid) => Some(*vid),
+                PortMode::Trunk(_) => None,
+            }
+        }
+    }
+
+    /// Builds a wire-ready copy of `pkt` for egress onto a port in
+    /// `out_mode`: adds an 802.1Q tag for `vid` if egress is a trunk and
+    /// `pkt` arrived untagged, strips the tag if egress is access and `pkt`
+    /// arrived tagged, and otherwise copies it through unchanged. Needed
+    /// because a client frame crossing the access/trunk boundary must be
+    /// re-encoded, not relayed byte-for-byte as it was received.
+    fn frame_for_egress<'a>(
+        buf: &'a mut Vec<u8>,
+        pkt: &EthernetPacket,
+        vid: u16,
+        out_mode: &PortMode,
+    ) -> EthernetPacket<'a> {
+        let is_tagged = pkt.get_ethertype() == EtherTypes::Vlan;
+        match (out_mode, is_tagged) {
+            (PortMode::Trunk(_), false) => Self::tag_frame(buf, pkt, vid),
+            (PortMode::Access(_), true) => Self::untag_frame(buf, pkt),
+            _ => {
+                buf.clear();
+                buf.extend_from_slice(pkt.packet());
+                EthernetPacket::new(buf).expect("a byte-for-byte copy of a valid packet is itself valid")
+            }
+        }
+    }
+
+    /// Wraps `pkt` in an 802.1Q header carrying `vid`, for egress onto a
+    /// trunk port.
+    fn tag_frame<'a>(buf: &'a mut Vec<u8>, pkt: &EthernetPacket, vid: u16) -> EthernetPacket<'a> {
+        buf.clear();
+        buf.resize(pkt.packet().len() + VlanPacket::minimum_packet_size(), 0);
+        {
+            let mut framed = MutableEthernetPacket::new(buf)
+                .expect("buf is sized for an ethernet header plus a vlan-tagged payload");
+            framed.set_destination(pkt.get_destination());
+            framed.set_source(pkt.get_source());
+            framed.set_ethertype(EtherTypes::Vlan);
+            let mut vlan = MutableVlanPacket::new(framed.payload_mut())
+                .expect("framed's payload was sized to hold a vlan header plus the original payload");
+            vlan.set_vlan_identifier(vid);
+            vlan.set_ethertype(pkt.get_ethertype());
+            vlan.set_payload(pkt.payload());
+        }
+        EthernetPacket::new(buf).expect("buf holds the tagged frame just written")
+    }
+
+    /// Strips `pkt`'s 802.1Q header, for egress onto an access port.
+    fn untag_frame<'a>(buf: &'a mut Vec<u8>, pkt: &EthernetPacket) -> EthernetPacket<'a> {
+        let vlan = VlanPacket::new(pkt.payload())
+            .expect("a frame with EtherTypes::Vlan should always carry a full vlan header");
+        buf.clear();
+        buf.resize(pkt.packet().len() - VlanPacket::minimum_packet_size(), 0);
+        {
+            let mut framed = MutableEthernetPacket::new(buf)
+                .expect("buf is sized for an ethernet header plus the untagged payload");
+            framed.set_destination(pkt.get_destination());
+            framed.set_source(pkt.get_source());
+            framed.set_ethertype(vlan.get_ethertype());
+            framed.set_payload(vlan.payload());
+        }
+        EthernetPacket::new(buf).expect("buf holds the untagged frame just written")
     }
 
     /// Forwards client packets (not control) using the forwarding table.
-    /// Learns source/port pairs when possible.
+    /// Learns source/port pairs when possible. Frames only ever egress
+    /// ports that are members of the frame's VLAN.
     fn fwd_client(&mut self, portnum_in: usize, eth_pkt: &EthernetPacket) {
         assert_ne!(
             eth_pkt.get_destination(),
@@ -150,77 +599,299 @@ impl EthRouter {
             "These should only be host to host packets"
         );
 
-        let inbound_state = self.ports[portnum_in].state;
+        let Some(vid) = self.frame_vid(portnum_in, eth_pkt) else {
+            eprintln!("Denied client packet outside this port's vlan membership: {eth_pkt:#?}");
+            return;
+        };
 
-        if inbound_state == PortState::Block {
-            // deny client packets from blocked ports.
+        let inbound_state = self.ports[portnum_in]
+            .state(vid)
+            .expect("frame_vid only returns VIDs the ingress port is a member of");
+
+        if matches!(
+            inbound_state,
+            PortState::Alternate | PortState::Backup | PortState::Proposing
+        ) {
+            // deny client packets from blocked (or not-yet-synced, proposing) ports.
             eprintln!("Denied client packet on a blocked port: {eth_pkt:#?}");
             return;
         };
 
-        // self learning
-        *self.fwd_table.entry(eth_pkt.get_source()).or_default() = portnum_in;
+        // self learning, scoped to this frame's vlan
+        self.learn(eth_pkt.get_source(), vid, portnum_in);
 
         if inbound_state == PortState::Learning {
             // No forwarding during learning
             return;
         }
 
-        // forward to known destination
-        if let Some(next_hop) = self.fwd_table.get(&eth_pkt.get_destination()) {
-            let port = &mut self.ports[*next_hop];
-            assert_ne!(
-                port.state,
-                PortState::Block,
+        // forward to known, live destination
+        if let Some(next_hop) = self.lookup(eth_pkt.get_destination(), vid) {
+            let port = &mut self.ports[next_hop];
+            assert!(
+                port.state(vid).is_some_and(PortState::is_forwarding),
                 "The forwarding table shouldn't suggest blocked ports."
             );
-            Self::send(&mut port.tx, eth_pkt);
+            let mut buf = Vec::new();
+            let out_pkt = Self::frame_for_egress(&mut buf, eth_pkt, vid, &port.mode);
+            Self::send(&mut port.iface, &out_pkt);
             return;
         }
 
-        // flood to unknown destination
+        // flood to unknown destination, restricted to this vlan's members
         for (portnum_out, port) in self.ports.iter_mut().enumerate() {
-            if portnum_out == portnum_in {
+            if portnum_out == portnum_in || !port.mode.has_vlan(vid) {
                 continue;
             }
-            match port.state {
-                PortState::Block | PortState::Learning => continue,
-                PortState::Root | PortState::Forward => Self::send(&mut port.tx, eth_pkt),
-            };
+            if port.state(vid).is_some_and(PortState::is_forwarding) {
+                let mut buf = Vec::new();
+                let out_pkt = Self::frame_for_egress(&mut buf, eth_pkt, vid, &port.mode);
+                Self::send(&mut port.iface, &out_pkt);
+            }
         }
     }
 
-    /// Makes a control packet with the current bpdu and sends it to all neighbors
-    /// (including blocked neighbors).
+    /// Makes a control packet for each VLAN's current bpdu and sends it to
+    /// every port that serves that VLAN (including blocked ports): untagged
+    /// on access ports, 802.1Q-tagged on trunk ports. A port that's
+    /// currently `Proposing` for that VLAN sends the proposal-bit variant,
+    /// asking whoever's downstream to sync and converge immediately rather
+    /// than wait out `forward_delay`. Also sets the tc bit on every bpdu
+    /// sent while a topology-change window is open and its relay budget
+    /// (`tc_hops_remaining`) isn't exhausted, so the notice reaches every
+    /// neighbor on the next hello.
     fn broadcast_bpdu(&mut self) {
-        let pkt = self
-            .curr_bpdu
-            .make_packet(&mut self.bpdu_buf, self.switch_id);
-        for port in &mut self.ports {
-            Self::send(&mut port.tx, &pkt);
+        let vids: Vec<u16> = self.curr_bpdu.keys().copied().collect();
+        let tc_active = self.tc_active() && self.tc_hops_remaining > 0;
+        for vid in vids {
+            let bpdu = self.curr_bpdu[&vid];
+            let bpdu = if tc_active {
+                bpdu.with_tc().with_tc_hops(self.tc_hops_remaining)
+            } else {
+                bpdu
+            };
+            let proposing_bpdu = bpdu.with_proposal();
+
+            for port in &mut self.ports {
+                if !matches!(&port.mode, PortMode::Access(access_vid) if *access_vid == vid) {
+                    continue;
+                }
+                let outgoing = if port.state(vid) == Some(PortState::Proposing) {
+                    proposing_bpdu
+                } else {
+                    bpdu
+                };
+                let pkt = outgoing.make_packet(&mut self.bpdu_buf, self.switch_id);
+                Self::send(&mut port.iface, &pkt);
+            }
+
+            for port in &mut self.ports {
+                if !matches!(&port.mode, PortMode::Trunk(vids) if vids.contains(&vid)) {
+                    continue;
+                }
+                let outgoing = if port.state(vid) == Some(PortState::Proposing) {
+                    proposing_bpdu
+                } else {
+                    bpdu
+                };
+                let pkt = outgoing.make_tagged_packet(&mut self.tagged_bpdu_buf, self.switch_id, vid);
+                Self::send(&mut port.iface, &pkt);
+            }
         }
     }
 
-    /// Blocks the current root port, replacing them with the new root. Marks
-    /// the new root as root.
-    /// Also overwrites the current bpdu with the neighbor's cost-adjusted bpdu.
-    fn reset_root(&mut self, new_root: usize, neighbor: &Bpdu, pkt: &EthernetPacket) {
+    /// Replies to a proposal on `portnum` with `vid`'s current bpdu, marked
+    /// with the agreement bit. Sent only out the one port that proposed,
+    /// not broadcast, telling the proposer it's clear to move straight to
+    /// forwarding without waiting on `forward_delay`.
+    fn send_agreement(&mut self, vid: u16, portnum: usize) {
+        let Some(&curr) = self.curr_bpdu.get(&vid) else {
+            return;
+        };
+        let reply = curr.with_agreement();
+        let port = &mut self.ports[portnum];
+        let pkt = match &port.mode {
+            PortMode::Access(_) => reply.make_packet(&mut self.bpdu_buf, self.switch_id),
+            PortMode::Trunk(_) => reply.make_tagged_packet(&mut self.tagged_bpdu_buf, self.switch_id, vid),
+        };
+        Self::send(&mut port.iface, &pkt);
+    }
+
+    /// Adopts `neighbor`'s superior information as `vid`'s new root path:
+    /// the port it arrived on becomes root, the previous root port (if any)
+    /// becomes an alternate, and every other designated (or still-proposing),
+    /// non-edge port serving `vid` is dropped back to `Learning` to sync
+    /// rather than risk forwarding into a transient loop while the tree is
+    /// still settling.
+    /// Also overwrites `vid`'s current bpdu with the neighbor's cost-adjusted
+    /// bpdu, inheriting the neighbor's timers since only the root's timers
+    /// are meant to govern the whole tree. If the neighbor proposed, replies
+    /// immediately with an agreement so its port can skip straight to
+    /// forwarding too.
+    fn reset_root(&mut self, vid: u16, new_root: usize, neighbor: &Bpdu, pkt: &EthernetPacket) {
         for (port_num, port) in self.ports.iter_mut().enumerate() {
+            if !port.mode.has_vlan(vid) {
+                continue;
+            }
             if port_num == new_root {
-                port.state = PortState::Root;
+                port.set_state(vid, PortState::Root);
+                continue;
+            }
+            match port.state(vid) {
+                Some(PortState::Root) => port.set_state(vid, PortState::Alternate),
+                Some(PortState::Designated | PortState::Proposing) if !port.is_edge(vid) => {
+                    port.set_state(vid, PortState::Learning);
+                }
+                _ => {}
+            }
+        }
+        self.curr_bpdu.insert(
+            vid,
+            Bpdu::new(
+                neighbor.cost() + 1,
+                neighbor.root_id(),
+                pkt.get_source(),
+                neighbor.max_age(),
+                neighbor.hello_time(),
+                neighbor.forward_delay(),
+            )
+            .with_message_age(neighbor.message_age()),
+        );
+        if neighbor.is_proposal() {
+            self.send_agreement(vid, new_root);
+        }
+        self.note_topology_change(vid);
+    }
+
+    /// Ages every port's most recently heard bpdu for every VLAN, discarding
+    /// whatever has exceeded its `max_age`, then re-runs root election for
+    /// any VLAN that lost information this way. Without this, a dead root
+    /// (or a dead path to it) is never detected: `curr_bpdu` would never be
+    /// invalidated, and a port a loop once put in `Alternate` would never
+    /// reactivate even after its competing path disappeared.
+    fn age_bpdus(&mut self) {
+        let mut vids_to_reelect = HashSet::new();
+        for port in &mut self.ports {
+            // `heard_at` is left untouched: it's the instant this bpdu
+            // actually arrived, and `bpdu` is aged from it fresh on every
+            // pass. Folding the aged copy back in and resetting the clock
+            // each tick would floor `elapsed` to under one `hello_time`
+            // forever (this runs far more often than a hello interval), so
+            // `message_age` would never climb and a dead root would never
+            // be detected.
+            port.heard_bpdu.retain(|&vid, (bpdu, heard_at)| {
+                if bpdu.aged_by(heard_at.elapsed()).is_expired() {
+                    vids_to_reelect.insert(vid);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        for vid in vids_to_reelect {
+            self.reelect_root(vid);
+        }
+    }
+
+    /// Re-runs root election for `vid` from scratch using every port's
+    /// most recently heard, still-live bpdu for that VLAN. Called whenever
+    /// `age_bpdus` discards stale information, so a backup path can take
+    /// over as root port, or a previously blocked port can start proposing
+    /// its way to designated, without waiting for a fresh bpdu to arrive on
+    /// its own.
+    /// Always ends by opening a topology-change window via
+    /// `note_topology_change`, since reaching this function at all means
+    /// `vid`'s topology just lost a path.
+    fn reelect_root(&mut self, vid: u16) {
+        let candidates: Vec<(usize, Bpdu)> = self
+            .ports
+            .iter()
+            .enumerate()
+            .filter(|(_, port)| port.mode.has_vlan(vid))
+            .filter_map(|(portnum, port)| port.heard_bpdu.get(&vid).map(|&(bpdu, _)| (portnum, bpdu)))
+            .collect();
+
+        let timers = self.curr_bpdu[&vid];
+
+        let Some(&(root_port, best)) = candidates
+            .iter()
+            .min_by_key(|(_, bpdu)| (bpdu.root_id(), bpdu.cost()))
+        else {
+            // No live neighbor information left for this vlan: this bridge
+            // has nothing left to defer to, so it becomes its own root.
+            self.curr_bpdu.insert(
+                vid,
+                Bpdu::new(
+                    0,
+                    self.switch_id,
+                    self.switch_id,
+                    timers.max_age(),
+                    timers.hello_time(),
+                    timers.forward_delay(),
+                ),
+            );
+            for port in &mut self.ports {
+                if port.mode.has_vlan(vid) {
+                    port.set_state(vid, PortState::Proposing);
+                }
+            }
+            self.note_topology_change(vid);
+            return;
+        };
+
+        let our_cost = best.cost() + 1;
+        self.curr_bpdu.insert(
+            vid,
+            Bpdu::new(
+                our_cost,
+                best.root_id(),
+                best.bridge_id(),
+                best.max_age(),
+                best.hello_time(),
+                best.forward_delay(),
+            )
+            .with_message_age(best.message_age()),
+        );
+
+        for (portnum, port) in self.ports.iter_mut().enumerate() {
+            if !port.mode.has_vlan(vid) {
                 continue;
             }
-            if port.state == PortState::Root {
-                port.state = PortState::Block;
+            if portnum == root_port {
+                port.set_state(vid, PortState::Root);
+                continue;
             }
+            let Some((_, bpdu)) = candidates.iter().find(|(p, _)| *p == portnum) else {
+                // No live bpdu on this port for this vlan right now; let it
+                // relearn through the normal Learning -> Designated/Alternate path.
+                port.set_state(vid, PortState::Learning);
+                continue;
+            };
+            let state = match (bpdu.cost() + 1).cmp(&our_cost) {
+                Ordering::Less | Ordering::Equal => PortState::Alternate,
+                Ordering::Greater => {
+                    if bpdu.bridge_id() == self.switch_id {
+                        PortState::Proposing
+                    } else {
+                        PortState::Backup
+                    }
+                }
+            };
+            port.set_state(vid, state);
         }
-        self.curr_bpdu = Bpdu::new(neighbor.cost() + 1, neighbor.root_id(), pkt.get_source());
+        self.note_topology_change(vid);
     }
 
     /// Runs packet control and forwarding as long as the network is live.
-    /// Startup duration is the amount of time switches spend learning the
-    /// topology and negotiating the spanning tree before beginning to route
-    /// host packets. Recommended between 500 ms and 2 seconds.
+    /// A port leaves `Learning` or `Proposing` for `Designated` once it has
+    /// spent its VLAN's `forward_delay` there, so a port that comes up after
+    /// the rest of the tree has converged still waits out its own learning
+    /// window instead of relying on one global startup clock. Two things can
+    /// skip that wait: the port gets marked an edge port after
+    /// `EDGE_DETECT_WINDOW` passes with no live bpdu held for it (it's
+    /// serving a host, not a bridge), or it receives a bpdu with the
+    /// agreement bit set, meaning whoever's downstream has already synced
+    /// and is ready for it.
     ///
     /// There were two accessible ways to implement this given the constraints of
     /// the pnet channel: (1) spawn a thread for each port and send
@@ -230,88 +901,298 @@ impl EthRouter {
     /// run +16 switches on a single emulated network on qemu on a macbook. There
     /// will be zero free cores no matter what, so a busy loop actually seems
     /// more efficient than multithreading + blocking in this situation.
-    pub fn run(mut self, startup_duration: Duration) -> anyhow::Result<()> {
-        let mut inbound = mem::take(&mut self.inbound);
-        assert_eq!(inbound.len(), self.ports.len());
+    pub fn run(mut self) -> anyhow::Result<()> {
+        loop {
+            self.tick()?;
+        }
+    }
+
+    /// One pass of `run`'s loop body: promotes ports out of `Learning`/
+    /// `Proposing` where due, resends bpdus and runs housekeeping on their
+    /// own cadences, ages heard bpdus, then drains one pending frame per
+    /// port. Split out from `run` so tests can drive a bounded number of
+    /// passes directly (over `ChannelInterface` links) and inspect the
+    /// resulting `PortState` afterward, instead of calling `run`, which
+    /// never returns.
+    fn tick(&mut self) -> anyhow::Result<()> {
+        for port in &mut self.ports {
+            let vids: Vec<u16> = port.vlan_states.keys().copied().collect();
+            for vid in vids {
+                if !matches!(port.state(vid), Some(PortState::Learning | PortState::Proposing)) {
+                    continue;
+                }
+                let Some(&since) = port.learning_since.get(&vid) else {
+                    continue;
+                };
+                let Some(curr) = self.curr_bpdu.get(&vid) else {
+                    continue;
+                };
 
-        let time_entered = Instant::now();
-        let mut init_phase = true;
+                let agreed = port.heard_bpdu.get(&vid).is_some_and(|(b, _)| b.is_agreement());
+                if agreed {
+                    port.set_state(vid, PortState::Designated);
+                    continue;
+                }
 
-        loop {
-            if init_phase && time_entered.elapsed() > startup_duration {
-                for port in &mut self.ports {
-                    // Assume by now that all ports that aren't otherwise assigned
-                    // are either silent or hosts.
-                    if port.state == PortState::Learning {
-                        port.state = PortState::Forward;
-                    }
+                if !port.heard_bpdu.contains_key(&vid) && since.elapsed() > EDGE_DETECT_WINDOW {
+                    port.edge.insert(vid, true);
+                    port.set_state(vid, PortState::Designated);
+                    continue;
+                }
+
+                let forward_delay = Duration::from_secs(curr.forward_delay() as u64);
+                if since.elapsed() > forward_delay {
+                    port.set_state(vid, PortState::Designated);
                 }
-                init_phase = false;
             }
+        }
 
-            if self.bpdu_resend_timeout < self.last_resent_bpdu.elapsed() {
-                self.broadcast_bpdu();
-                self.last_resent_bpdu = Instant::now();
+        if self.bpdu_resend_timeout < self.last_resent_bpdu.elapsed() {
+            // Advance every relayed vlan bpdu by however many hello intervals
+            // have actually elapsed since we last relayed it, before sending
+            // it back out. A relaying bridge is never the root, so unless
+            // `message_age` climbs on the way through, it stays pinned at
+            // whatever the root originated it at and a dead upstream bridge
+            // can never be aged out downstream. A bpdu naming this switch as
+            // root is freshly originated on every hello and stays at age 0.
+            let elapsed = self.last_resent_bpdu.elapsed();
+            for bpdu in self.curr_bpdu.values_mut() {
+                if bpdu.root_id() != self.switch_id {
+                    *bpdu = bpdu.aged_by(elapsed);
+                }
             }
+            self.broadcast_bpdu();
+            self.last_resent_bpdu = Instant::now();
+        }
 
-            for (portnum_in, rx) in inbound.iter_mut().enumerate() {
-                let bytes = match rx.next() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        if e.kind() == ErrorKind::TimedOut {
-                            continue;
-                        }
-                        bail!("Exiting on io error: {e:#?}");
-                    }
-                };
-                let Some(eth_pkt) = EthernetPacket::new(bytes) else {
-                    eprintln!("Failed to parse packet: {bytes:#?}");
-                    continue;
-                };
+        // Housekeep more often while a topology-change window is open,
+        // scaled the same way as the normal cadence, so the shortened
+        // `tc_fwd_age_timeout` actually gets to evict stale entries
+        // before the (much shorter) tc window closes again.
+        let housekeep_cadence = if self.tc_active() {
+            self.tc_fwd_age_timeout / HOUSEKEEP_FRACTION
+        } else {
+            self.fwd_age_timeout / HOUSEKEEP_FRACTION
+        };
+        if housekeep_cadence < self.last_housekeep.elapsed() {
+            self.housekeep();
+            self.last_housekeep = Instant::now();
+        }
 
-                let Some(neighbor) = EthPort::try_routing(&eth_pkt) else {
-                    self.fwd_client(portnum_in, &eth_pkt);
-                    continue;
-                };
+        self.age_bpdus();
 
-                // first take the smaller root id
-                // then take the shortest path to the smallest root id
-                let agree_on_root = match neighbor.root_id().cmp(&self.curr_bpdu.root_id()) {
-                    Ordering::Less => {
-                        self.reset_root(portnum_in, neighbor, &eth_pkt);
-                        self.broadcast_bpdu();
+        for portnum_in in 0..self.ports.len() {
+            let bytes = match self.ports[portnum_in].iface.read() {
+                Ok(bytes) => bytes.to_vec(),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
                         continue;
                     }
-                    Ordering::Greater => {
-                        self.broadcast_bpdu();
+                    bail!("Exiting on io error: {e:#?}");
+                }
+            };
+            let Some(eth_pkt) = EthernetPacket::new(&bytes) else {
+                eprintln!("Failed to parse packet: {bytes:#?}");
+                continue;
+            };
+
+            let Some((neighbor, tagged_vid)) = Bpdu::try_routing(&eth_pkt) else {
+                self.fwd_client(portnum_in, &eth_pkt);
+                continue;
+            };
+
+            // An untagged bpdu belongs to its access port's own vlan; a
+            // tagged one names its vlan directly, but only if this port
+            // actually serves it.
+            let vid = match tagged_vid {
+                Some(v) if self.ports[portnum_in].mode.has_vlan(v) => v,
+                Some(_) => {
+                    eprintln!("Ignoring bpdu tagged for a vlan this port doesn't serve: {eth_pkt:#?}");
+                    continue;
+                }
+                None => match self.ports[portnum_in].mode {
+                    PortMode::Access(access_vid) => access_vid,
+                    PortMode::Trunk(_) => {
+                        eprintln!("Ignoring untagged bpdu on a trunk port: {eth_pkt:#?}");
                         continue;
                     }
-                    Ordering::Equal => true,
-                };
-                assert!(
-                    agree_on_root,
-                    "The code below only applies to switches that already agree on the root"
-                );
+                },
+            };
+
+            self.ports[portnum_in]
+                .heard_bpdu
+                .insert(vid, (neighbor, Instant::now()));
+            // A bpdu arrived, so there's a bridge behind this port after
+            // all; it was never really an edge port.
+            self.ports[portnum_in].edge.insert(vid, false);
+
+            if neighbor.is_tc() {
+                // A neighbor is relaying a topology change from
+                // elsewhere in the tree; adopt the same fast aging and
+                // keep relaying it on our next broadcast, bounded by the
+                // hop budget it arrived with so mutual relay terminates.
+                self.relay_topology_change(vid, neighbor.tc_hops());
+            }
+
+            let Some(&curr) = self.curr_bpdu.get(&vid) else {
+                eprintln!("Ignoring bpdu for an unconfigured vlan {vid}: {eth_pkt:#?}");
+                continue;
+            };
+
+            // first take the smaller root id
+            // then take the shortest path to the smallest root id
+            let agree_on_root = match neighbor.root_id().cmp(&curr.root_id()) {
+                Ordering::Less => {
+                    self.reset_root(vid, portnum_in, &neighbor, &eth_pkt);
+                    self.broadcast_bpdu();
+                    continue;
+                }
+                Ordering::Greater => {
+                    if self.ports[portnum_in].state(vid) == Some(PortState::Root) {
+                        // Our own root port just reported worse/different
+                        // root info than what we're currently holding: the
+                        // path we were deferring to has withdrawn, so
+                        // re-elect instead of leaving curr_bpdu pointing at
+                        // a root that's no longer being advertised.
+                        self.reelect_root(vid);
+                    }
+                    self.broadcast_bpdu();
+                    continue;
+                }
+                Ordering::Equal => true,
+            };
+            assert!(
+                agree_on_root,
+                "The code below only applies to switches that already agree on the root"
+            );
 
-                match (neighbor.cost() + 1).cmp(&self.curr_bpdu.cost()) {
-                    Ordering::Less => {
-                        self.reset_root(portnum_in, neighbor, &eth_pkt);
-                        self.broadcast_bpdu();
+            match (neighbor.cost() + 1).cmp(&curr.cost()) {
+                Ordering::Less => {
+                    self.reset_root(vid, portnum_in, &neighbor, &eth_pkt);
+                    self.broadcast_bpdu();
+                }
+                Ordering::Equal => {
+                    if self.ports[portnum_in].state(vid) != Some(PortState::Root) {
+                        self.ports[portnum_in].set_state(vid, PortState::Alternate);
+                    } else {
+                        // A fresh hello from upstream on our root port: the
+                        // relayed info's age restarts from what it carries.
+                        self.curr_bpdu
+                            .insert(vid, curr.with_message_age(neighbor.message_age()));
                     }
-                    Ordering::Equal => {
-                        let port = &mut self.ports[portnum_in];
-                        if port.state != PortState::Root {
-                            port.state = PortState::Block;
+                }
+                Ordering::Greater => {
+                    let port = &mut self.ports[portnum_in];
+                    if neighbor.bridge_id() == self.switch_id {
+                        // This is a routine hello confirming we're still
+                        // the better path on this port. Only (re-)enter
+                        // Proposing the first time we win the role, so a
+                        // port that already converged to Designated isn't
+                        // knocked back into discarding on every hello.
+                        if port.state(vid) != Some(PortState::Designated) {
+                            port.set_state(vid, PortState::Proposing);
                         }
+                    } else {
+                        port.set_state(vid, PortState::Backup);
                     }
-                    Ordering::Greater => {
-                        self.ports[portnum_in].state = if neighbor.bridge_id() == self.switch_id {
-                            PortState::Forward
-                        } else {
-                            PortState::Block
-                        };
-                    }
-                };
+                }
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl EthRouter<PnetInterface> {
+    /// Queries ethernet interfaces and opens read/write connections with all
+    /// mininet ports. Assigns a mac address to represent the whole switch and
+    /// establishes an initial Bpdu for every VLAN this switch serves.
+    ///
+    /// `vlan_config` maps an interface name to the `PortMode` it should be
+    /// built with. Interfaces with no entry default to `PortMode::Access(1)`,
+    /// matching an unconfigured switch's single default VLAN.
+    pub fn build(
+        switch_name: &str,
+        vlan_config: &HashMap<String, PortMode>,
+        bpdu_resend_timeout: Duration,
+        fwd_age_timeout: Duration,
+        max_age: Duration,
+        forward_delay: Duration,
+        eth_poll_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        let mn_name = format!("{switch_name}-eth");
+        let mut ports = Vec::new();
+
+        for intf in datalink::interfaces()
+            .iter()
+            .filter(|intf| intf.name.contains(&mn_name))
+        {
+            let mode = vlan_config
+                .get(&intf.name)
+                .cloned()
+                .unwrap_or(PortMode::Access(1));
+            let iface = PnetInterface::build(intf, eth_poll_timeout)?;
+            ports.push((iface, mode));
+        }
+
+        Self::from_ports(ports, bpdu_resend_timeout, fwd_age_timeout, max_age, forward_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a single-port `EthRouter<ChannelInterface>`, identified by
+    /// `iface`'s mac, serving untagged VLAN 1 on the given end of a
+    /// `ChannelInterface` link.
+    fn single_port_router(iface: ChannelInterface) -> EthRouter<ChannelInterface> {
+        EthRouter::from_ports(
+            vec![(iface, PortMode::Access(1))],
+            Duration::from_millis(20),
+            Duration::from_secs(30),
+            Duration::from_secs(10),
+            Duration::from_millis(50),
+        )
+        .expect("a single valid port should build a router")
+    }
+
+    /// Wires two `EthRouter`s together over an in-process `ChannelInterface`
+    /// link and runs `tick` on both, in lockstep, until their single ports
+    /// converge to a stable root/designated pair or a deadline passes.
+    /// `EthRouter::run` never returns, so driving `tick` directly (rather
+    /// than spawning threads around `run`) is what lets a test observe the
+    /// converged `PortState` at all.
+    #[test]
+    fn two_bridges_converge_to_one_root_and_one_designated_port() {
+        let (link_lo, link_hi) = ChannelInterface::paired(
+            MacAddr(0, 0, 0, 0, 0, 1),
+            MacAddr(0, 0, 0, 0, 0, 2),
+            Some(Duration::from_millis(2)),
+        );
+        let mut lo = single_port_router(link_lo);
+        let mut hi = single_port_router(link_hi);
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            lo.tick().expect("lo should tick without error");
+            hi.tick().expect("hi should tick without error");
+
+            let lo_state = lo.ports[0].state(1);
+            let hi_state = hi.ports[0].state(1);
+            let converged = matches!(lo_state, Some(PortState::Root) | Some(PortState::Designated))
+                && matches!(hi_state, Some(PortState::Root) | Some(PortState::Designated));
+            if converged || Instant::now() > deadline {
+                assert!(converged, "ports never converged: lo={lo_state:?}, hi={hi_state:?}");
+                // The bridge with the lower mac (lo) wins the election and
+                // keeps its only port Designated; the other bridge's only
+                // path to it becomes its Root port. They should never agree
+                // on the same role, since that isn't a loop-free tree.
+                assert_ne!(
+                    lo_state, hi_state,
+                    "a two-bridge link should converge to one Root port and one Designated port"
+                );
+                break;
             }
         }
     }